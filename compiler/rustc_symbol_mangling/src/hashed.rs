@@ -37,9 +37,11 @@ pub(super) fn mangle<'tcx>(
 }
 
 // The hash is encoded based on `base-62` and the final terminator `_` is removed because it does
-// not help prevent hash collisions
+// not help prevent hash collisions. The result is written as the identifier `H<digits>`, so its
+// length prefix is the length of that whole `H`-prefixed identifier.
 fn push_hash64(hash: u64, output: &mut String) {
-    let hash = v0::encode_integer_62(hash);
-    let hash_len = hash.len();
-    let _ = write!(output, "{hash_len}H{}", &hash[..hash_len - 1]);
+    let mut digits = String::new();
+    v0::push_integer_62_no_term(hash, &mut digits);
+    let ident_len = 1 + digits.len(); // 'H' + digits
+    let _ = write!(output, "{ident_len}H{digits}");
 }
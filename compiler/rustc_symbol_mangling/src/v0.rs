@@ -1,3 +1,49 @@
+//! The `v0` symbol mangling scheme, as specified in [RFC 2603] and the
+//! [v0 chapter of the rustc book][book].
+//!
+//! Which part of this module produces each rule of the grammar:
+//!
+//! | Grammar rule                                               | Printed by                                |
+//! |------------------------------------------------------------|-------------------------------------------|
+//! | `<symbol-name>`, `<instantiating-crate>`                   | `mangle`                                  |
+//! | `<path>`: `C <identifier>` (crate root)                    | `print_crate_name` [^internal]            |
+//! | `<path>`: `M <impl-path> <type>`                           | `print_impl_path`                         |
+//! | `<path>`: `X <impl-path> <type> <path>`                    | `print_impl_path`                         |
+//! | `<path>`: `Y <type> <path>`                                | `print_path_with_qualified`               |
+//! | `<path>`: `N <namespace> <path> <identifier>`              | `path_append_ns`                          |
+//! | `<path>`: `I <path> {<generic-arg>} E`                     | `print_path_with_generic_args`            |
+//! | `<backref>` (in `<path>`, `<type>`, `<const>`)             | `print_backref`                           |
+//! | `<impl-path>`                                              | `print_impl_path` [^impl-path]            |
+//! | `<namespace>`                                              | `print_path_with_simple` [^namespace]     |
+//! | `<disambiguator>`                                          | `push_disambiguator`                      |
+//! | `<identifier>`                                             | `push_disambiguator` + `push_ident`       |
+//! | `<undisambiguated-identifier>`, `<bytes>`                  | `push_ident` / `try_push_ident`           |
+//! | `<decimal-number>`                                         | `push_ident` [^decimal]                   |
+//! | `<generic-arg>`                                            | `print_path_with_generic_args` [^generic] |
+//! | `<lifetime>`                                               | `print_region`                            |
+//! | `<binder>`                                                 | `wrap_binder`                             |
+//! | `<type>`, `<basic-type>`, `<fn-sig>`, `<abi>`              | `print_type`                              |
+//! | `<dyn-bounds>`, `<dyn-trait>`, `<dyn-trait-assoc-binding>` | `print_dyn_existential`                   |
+//! | `<const>`, `<const-data>`                                  | `print_const`                             |
+//! | `<base-62-number>`                                         | `push_integer_62`                         |
+//! | `<vendor-specific-suffix>`                                 | not emitted by rustc                      |
+//!
+//! [^internal]: `mangle_internal_symbol` also pushes its own `C` crate root,
+//!     with a disambiguator derived from the rustc version.
+//! [^impl-path]: Including the impl disambiguator and, for trait impls with
+//!     generic parameters, the `NI` path naming the impl itself.
+//! [^namespace]: The `S` namespace of shims is chosen in `mangle`, and
+//!     `mangle_internal_symbol` passes `v` to `path_append_ns` directly.
+//! [^decimal]: Only as the length prefix of identifiers; the optional encoding
+//!     version after `_R` is never emitted.
+//! [^generic]: Which also prints the `K` prefix in front of const arguments.
+//!
+//! Pattern types (`W <type> <pattern-kind>`, printed by `print_pat`) postdate
+//! the RFC and are only described in the book.
+//!
+//! [RFC 2603]: https://rust-lang.github.io/rfcs/2603-rust-symbol-name-mangling-v0.html
+//! [book]: https://doc.rust-lang.org/nightly/rustc/symbol-mangling/v0.html
+
 use std::fmt::Write;
 use std::hash::Hasher;
 use std::iter;
@@ -32,17 +78,7 @@ pub(super) fn mangle<'tcx>(
     // FIXME(eddyb) this should ideally not be needed.
     let args = tcx.normalize_erasing_regions(ty::TypingEnv::fully_monomorphized(), instance.args);
 
-    let prefix = "_R";
-    let mut p: V0SymbolMangler<'_> = V0SymbolMangler {
-        tcx,
-        start_offset: prefix.len(),
-        is_exportable,
-        paths: FxHashMap::default(),
-        types: FxHashMap::default(),
-        consts: FxHashMap::default(),
-        binders: vec![],
-        out: String::from(prefix),
-    };
+    let mut p = V0SymbolMangler::with_output(tcx, is_exportable, String::from("_R"));
 
     // Append `::{shim:...#0}` to shims that can coexist with a non-shim instance.
     let shim_kind = match instance.def {
@@ -78,7 +114,7 @@ pub(super) fn mangle<'tcx>(
     if let Some(instantiating_crate) = instantiating_crate {
         p.print_def_path(instantiating_crate.as_def_id(), &[]).unwrap();
     }
-    std::mem::take(&mut p.out)
+    p.into_string()
 }
 
 pub fn mangle_internal_symbol<'tcx>(tcx: TyCtxt<'tcx>, item_name: &str) -> String {
@@ -91,17 +127,7 @@ pub fn mangle_internal_symbol<'tcx>(tcx: TyCtxt<'tcx>, item_name: &str) -> Strin
         _ => {}
     }
 
-    let prefix = "_R";
-    let mut p: V0SymbolMangler<'_> = V0SymbolMangler {
-        tcx,
-        start_offset: prefix.len(),
-        is_exportable: false,
-        paths: FxHashMap::default(),
-        types: FxHashMap::default(),
-        consts: FxHashMap::default(),
-        binders: vec![],
-        out: String::from(prefix),
-    };
+    let mut p = V0SymbolMangler::with_output(tcx, false, String::from("_R"));
 
     p.path_append_ns(
         |p| {
@@ -127,7 +153,7 @@ pub fn mangle_internal_symbol<'tcx>(tcx: TyCtxt<'tcx>, item_name: &str) -> Strin
     )
     .unwrap();
 
-    std::mem::take(&mut p.out)
+    p.into_string()
 }
 
 pub(super) fn mangle_typeid_for_trait_ref<'tcx>(
@@ -135,18 +161,9 @@ pub(super) fn mangle_typeid_for_trait_ref<'tcx>(
     trait_ref: ty::ExistentialTraitRef<'tcx>,
 ) -> String {
     // FIXME(flip1995): See comment in `mangle_typeid_for_fnabi`.
-    let mut p = V0SymbolMangler {
-        tcx,
-        start_offset: 0,
-        is_exportable: false,
-        paths: FxHashMap::default(),
-        types: FxHashMap::default(),
-        consts: FxHashMap::default(),
-        binders: vec![],
-        out: String::new(),
-    };
+    let mut p = V0SymbolMangler::with_output(tcx, false, String::new());
     p.print_def_path(trait_ref.def_id, &[]).unwrap();
-    std::mem::take(&mut p.out)
+    p.into_string()
 }
 
 struct BinderLevel {
@@ -169,7 +186,9 @@ struct V0SymbolMangler<'tcx> {
     out: String,
     is_exportable: bool,
 
-    /// The length of the prefix in `out` (e.g. 2 for `_R`).
+    /// The length of the prefix in `out` that backrefs are relative to, i.e.
+    /// 2 for `_R`, or everything `out` held when the mangler was created if it
+    /// didn't start with `_R` (see `with_output`).
     start_offset: usize,
     /// The values are start positions in `out`, in bytes.
     paths: FxHashMap<(DefId, &'tcx [GenericArg<'tcx>]), usize>,
@@ -178,6 +197,32 @@ struct V0SymbolMangler<'tcx> {
 }
 
 impl<'tcx> V0SymbolMangler<'tcx> {
+    /// Create a mangler that appends to `out`.
+    ///
+    /// If `out` starts with `_R`, it is taken to be a (possibly partially
+    /// built) symbol, and backref positions are counted from just after the
+    /// `_R`, as the RFC requires. Otherwise, all of `out` is treated as an
+    /// opaque prefix that backrefs can't point into, e.g. the empty string
+    /// when printing a bare path.
+    fn with_output(tcx: TyCtxt<'tcx>, is_exportable: bool, out: String) -> Self {
+        let start_offset = if out.starts_with("_R") { "_R".len() } else { out.len() };
+        V0SymbolMangler {
+            tcx,
+            binders: vec![],
+            out,
+            is_exportable,
+            start_offset,
+            paths: FxHashMap::default(),
+            types: FxHashMap::default(),
+            consts: FxHashMap::default(),
+        }
+    }
+
+    /// Consume the mangler, returning everything printed so far.
+    fn into_string(self) -> String {
+        self.out
+    }
+
     fn push(&mut self, s: &str) {
         self.out.push_str(s);
     }
@@ -451,9 +496,12 @@ fn print_type(&mut self, ty: Ty<'tcx>) -> Result<(), PrintError> {
             ty::Uint(UintTy::U64) => "y",
             ty::Uint(UintTy::U128) => "o",
             ty::Uint(UintTy::Usize) => "j",
-            ty::Float(FloatTy::F16) => "C3f16",
             ty::Float(FloatTy::F32) => "f",
             ty::Float(FloatTy::F64) => "d",
+            // RFC 2603 predates `f16` and `f128` and reserves no tag for them,
+            // so they are printed as crate roots named after the type, which
+            // demanglers already render as just `f16` / `f128`.
+            ty::Float(FloatTy::F16) => "C3f16",
             ty::Float(FloatTy::F128) => "C4f128",
             ty::Never => "z",
 
@@ -953,25 +1001,49 @@ fn print_path_with_generic_args(
 /// * `x > 0` is encoded as `x - 1` in base 62, followed by `"_"`,
 ///   e.g. `1` becomes `"0_"`, `62` becomes `"Z_"`, etc.
 pub(crate) fn push_integer_62(x: u64, output: &mut String) {
+    push_integer_62_no_term(x, output);
+    output.push('_');
+}
+
+/// Push a base 62 integer in the same format as `push_integer_62`, but
+/// without the `"_"` terminator, e.g. `0` becomes `""` and `62` becomes `"Z"`.
+///
+/// This must only be used where the end of the number is already implied by
+/// the surrounding encoding (such as the length-prefixed hash in `hashed`),
+/// as it can't be decoded on its own. Everything following the RFC grammar,
+/// including backrefs, wants `push_integer_62`.
+pub(crate) fn push_integer_62_no_term(x: u64, output: &mut String) {
     if let Some(x) = x.checked_sub(1) {
         output.push_str(&x.to_base(62));
     }
-    output.push('_');
 }
 
-pub(crate) fn encode_integer_62(x: u64) -> String {
-    let mut output = String::new();
-    push_integer_62(x, &mut output);
-    output
+/// The first byte of an identifier that can't be encoded in a v0 symbol,
+/// i.e. an ASCII byte other than `_`, `a-z`, `A-Z` and `0-9`.
+pub(crate) struct InvalidIdentError {
+    pub(crate) byte: u8,
+    pub(crate) position: usize,
 }
 
+/// Push an `<identifier>`, punycode-encoding it when it isn't ASCII.
+///
+/// Idents reaching the mangler are expected to be valid already, so this
+/// ICEs on invalid input; use `try_push_ident` where that can't be relied upon.
 pub(crate) fn push_ident(ident: &str, output: &mut String) {
+    if let Err(InvalidIdentError { byte, position }) = try_push_ident(ident, output) {
+        bug!("symbol_names: bad byte {} at {} in ident {:?}", byte, position, ident);
+    }
+}
+
+/// Like `push_ident`, but returns an error instead of ICE-ing on bytes that
+/// can't be encoded. Nothing is pushed to `output` in that case.
+pub(crate) fn try_push_ident(ident: &str, output: &mut String) -> Result<(), InvalidIdentError> {
     let mut use_punycode = false;
-    for b in ident.bytes() {
+    for (position, b) in ident.bytes().enumerate() {
         match b {
             b'_' | b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => {}
             0x80..=0xff => use_punycode = true,
-            _ => bug!("symbol_names: bad byte {} in ident {:?}", b, ident),
+            byte => return Err(InvalidIdentError { byte, position }),
         }
     }
 
@@ -1005,4 +1077,5 @@ pub(crate) fn push_ident(ident: &str, output: &mut String) {
     }
 
     output.push_str(ident);
+    Ok(())
 }